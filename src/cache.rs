@@ -0,0 +1,175 @@
+//! HTTP response cache for TON gateway content.
+//!
+//! TON-site content served through `ton_gateway` is content-addressed and
+//! largely immutable, so repeated requests for the same rewritten URI are
+//! cheap to serve from memory. `ResponseCache` is an LRU-bounded store keyed
+//! by method + rewritten URI; `proxy_internal` consults it before calling
+//! `state.client.request` for TON domains and populates it from the response
+//! afterward, honoring `Cache-Control`/`max-age` and `Expires` for freshness.
+
+use std::num::NonZeroUsize;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+use anyhow::Result;
+use futures::StreamExt;
+use http::{HeaderMap, Method, Response, StatusCode, Uri};
+use hyper::body::{Bytes, HttpBody};
+use hyper::Body;
+use lru::LruCache;
+
+use crate::config::Config;
+
+struct Entry {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+    expires_at: Instant,
+}
+
+/// LRU-bounded cache of cacheable TON gateway responses
+pub struct ResponseCache {
+    entries: Mutex<LruCache<String, Entry>>,
+    default_ttl: Duration,
+    max_body_bytes: usize,
+}
+
+impl ResponseCache {
+    pub fn new(config: &Config) -> Self {
+        let capacity = NonZeroUsize::new(config.response_cache_size.max(1))
+            .expect("response_cache_size.max(1) is never zero");
+        Self {
+            entries: Mutex::new(LruCache::new(capacity)),
+            default_ttl: Duration::from_secs(config.response_cache_default_ttl_secs),
+            max_body_bytes: config.response_cache_max_body_bytes,
+        }
+    }
+
+    /// Look up a cached response for `method`/`uri`, if present and not yet expired
+    pub fn get(&self, method: &Method, uri: &Uri) -> Option<Response<Body>> {
+        let key = cache_key(method, uri);
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries.get(&key)?;
+        if entry.expires_at <= Instant::now() {
+            entries.pop(&key);
+            return None;
+        }
+
+        let mut response = Response::new(Body::from(entry.body.clone()));
+        *response.status_mut() = entry.status;
+        *response.headers_mut() = entry.headers.clone();
+        Some(response)
+    }
+
+    /// Cache `response` if it's a fresh, cacheable `GET` response, and hand
+    /// back an equivalent response for the caller to serve (the body must be
+    /// buffered to be cached, so the original can't be returned as-is).
+    /// Bodies over `max_body_bytes` are forwarded unbuffered instead, so a
+    /// single huge (or many large concurrent) response(s) can't blow past the
+    /// cache's intended memory footprint.
+    pub async fn store(&self, method: &Method, uri: &Uri, response: Response<Body>) -> Result<Response<Body>> {
+        if method != Method::GET || !is_cacheable(&response) {
+            return Ok(response);
+        }
+        let Some(ttl) = freshness_ttl(&response, self.default_ttl) else {
+            return Ok(response);
+        };
+
+        let (parts, body) = response.into_parts();
+        let body = match buffer_up_to(body, self.max_body_bytes).await? {
+            Ok(body) => body,
+            Err(body) => {
+                let mut response = Response::new(body);
+                *response.status_mut() = parts.status;
+                *response.headers_mut() = parts.headers;
+                return Ok(response);
+            }
+        };
+
+        self.entries.lock().unwrap().put(
+            cache_key(method, uri),
+            Entry {
+                status: parts.status,
+                headers: parts.headers.clone(),
+                body: body.clone(),
+                expires_at: Instant::now() + ttl,
+            },
+        );
+
+        let mut response = Response::new(Body::from(body));
+        *response.status_mut() = parts.status;
+        *response.headers_mut() = parts.headers;
+        Ok(response)
+    }
+}
+
+/// Buffer `body` into a single `Bytes`, unless doing so would take more than
+/// `max_bytes`, in which case buffering stops as soon as the cap is crossed
+/// and the chunks read so far are chained back onto the remaining stream, so
+/// the caller gets an equivalent, still-unbuffered body instead of losing data.
+async fn buffer_up_to(mut body: Body, max_bytes: usize) -> Result<std::result::Result<Bytes, Body>> {
+    let mut chunks = Vec::new();
+    let mut total = 0usize;
+
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk?;
+        total += chunk.len();
+        chunks.push(chunk);
+        if total > max_bytes {
+            let already_read = futures::stream::iter(chunks.into_iter().map(Ok::<_, hyper::Error>));
+            return Ok(Err(Body::wrap_stream(already_read.chain(body))));
+        }
+    }
+
+    let mut buf = Vec::with_capacity(total);
+    for chunk in chunks {
+        buf.extend_from_slice(&chunk);
+    }
+    Ok(Ok(Bytes::from(buf)))
+}
+
+fn cache_key(method: &Method, uri: &Uri) -> String {
+    format!("{} {}", method, uri)
+}
+
+fn is_cacheable(response: &Response<Body>) -> bool {
+    if response.status() != StatusCode::OK {
+        return false;
+    }
+    match cache_control(response) {
+        Some(cc) => !(cc.contains("no-store") || cc.contains("no-cache") || cc.contains("private")),
+        None => true,
+    }
+}
+
+/// How long a response should be considered fresh, from `Cache-Control: max-age`
+/// or `Expires`, falling back to `default_ttl`. `None` means it must not be cached.
+fn freshness_ttl(response: &Response<Body>, default_ttl: Duration) -> Option<Duration> {
+    if let Some(cc) = cache_control(response) {
+        for directive in cc.split(',') {
+            if let Some(seconds) = directive.trim().strip_prefix("max-age=") {
+                return match seconds.parse::<u64>() {
+                    Ok(0) => None,
+                    Ok(secs) => Some(Duration::from_secs(secs)),
+                    Err(_) => Some(default_ttl),
+                };
+            }
+        }
+    }
+
+    if let Some(expires) = response.headers().get(http::header::EXPIRES).and_then(|h| h.to_str().ok()) {
+        if let Ok(expires) = httpdate::parse_http_date(expires) {
+            return Some(expires.duration_since(SystemTime::now()).unwrap_or(Duration::ZERO));
+        }
+    }
+
+    Some(default_ttl)
+}
+
+fn cache_control(response: &Response<Body>) -> Option<String> {
+    response
+        .headers()
+        .get(http::header::CACHE_CONTROL)
+        .and_then(|h| h.to_str().ok())
+        .map(|v| v.to_ascii_lowercase())
+}
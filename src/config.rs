@@ -1,20 +1,171 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use base64::Engine;
+use http::Uri;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::fs::File;
 use std::io::Read;
-use std::path::Path;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use subtle::ConstantTimeEq;
+
+/// A username/password pair accepted via HTTP Basic proxy authentication
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct BasicUser {
+    pub username: String,
+    pub password: String,
+}
+
+/// A parent HTTP proxy to forward all outbound traffic through
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct UpstreamProxy {
+    /// Address of the upstream proxy, as `host:port`
+    pub address: String,
+
+    /// Username to authenticate to the upstream proxy with, if it requires it
+    #[serde(default)]
+    pub username: Option<String>,
+
+    /// Password to authenticate to the upstream proxy with, if it requires it
+    #[serde(default)]
+    pub password: Option<String>,
+}
+
+/// Wire format for an emitted PROXY protocol header
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ProxyProtocolVersion {
+    /// Human-readable text header (`PROXY TCP4 ...`)
+    #[default]
+    V1,
+    /// Compact binary header
+    V2,
+}
+
+/// Which destinations should receive a PROXY protocol header ahead of the
+/// rest of the connection, so they see the original client address
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct ProxyProtocolConfig {
+    /// Emit a header when forwarding to `ton_gateway`
+    #[serde(default)]
+    pub for_ton_gateway: bool,
+
+    /// Emit a header when `tunnel()` dials one of these destination hosts (suffix match)
+    #[serde(default)]
+    pub for_domains: Vec<String>,
+
+    /// Wire format to use
+    #[serde(default)]
+    pub version: ProxyProtocolVersion,
+}
+
+/// Root CA used to mint per-host leaf certificates for MITM interception
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TlsCa {
+    /// PEM-encoded CA certificate
+    pub cert_path: PathBuf,
+
+    /// PEM-encoded CA private key
+    pub key_path: PathBuf,
+}
+
+impl UpstreamProxy {
+    /// Build the `Proxy-Authorization` header value to send to the upstream proxy, if configured
+    pub fn authorization_header(&self) -> Option<String> {
+        let username = self.username.as_deref()?;
+        let password = self.password.as_deref().unwrap_or("");
+        let encoded = base64::engine::general_purpose::STANDARD
+            .encode(format!("{}:{}", username, password));
+        Some(format!("Basic {}", encoded))
+    }
+}
 
 /// Configuration for the TON proxy
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Config {
     /// List of TON domains to handle specially
     pub ton_domains: Vec<String>,
-    
+
     /// Default TON gateway to use for TON sites
     pub ton_gateway: String,
-    
+
     /// Whether to log detailed request information
     pub verbose_logging: bool,
+
+    /// Users allowed to authenticate via HTTP Basic in `Proxy-Authorization`.
+    /// If this and `auth_bearer_tokens` are both empty, proxy authentication is disabled.
+    #[serde(default)]
+    pub auth_basic_users: Vec<BasicUser>,
+
+    /// Bearer tokens allowed via `Proxy-Authorization: Bearer <token>`.
+    #[serde(default)]
+    pub auth_bearer_tokens: Vec<String>,
+
+    /// Parent HTTP proxy to forward all outbound traffic through, if any
+    #[serde(default)]
+    pub upstream_proxy: Option<UpstreamProxy>,
+
+    /// When a `CONNECT` tunnel's SNI reveals a TON domain, drop the connection
+    /// instead of routing it to `ton_gateway`
+    #[serde(default)]
+    pub block_ton_https: bool,
+
+    /// Maximum number of hostnames kept in the DNS resolver's LRU cache
+    #[serde(default = "default_dns_cache_size")]
+    pub dns_cache_size: usize,
+
+    /// Static hostname -> IP overrides that are served without a DNS lookup
+    #[serde(default)]
+    pub dns_static_hosts: HashMap<String, IpAddr>,
+
+    /// Root CA to intercept (MITM) HTTPS `CONNECT` tunnels to TON domains with,
+    /// so `rewrite_ton_uri` can apply inside HTTPS too. When unset, TON HTTPS
+    /// traffic stays on the opaque `tunnel()` pass-through.
+    #[serde(default)]
+    pub mitm_ca: Option<TlsCa>,
+
+    /// Maximum number of leaf certificates kept in the MITM CA's per-host cache
+    #[serde(default = "default_mitm_cert_cache_size")]
+    pub mitm_cert_cache_size: usize,
+
+    /// Maximum number of TON gateway responses kept in the response cache
+    #[serde(default = "default_response_cache_size")]
+    pub response_cache_size: usize,
+
+    /// Fallback freshness lifetime, in seconds, for cached responses that
+    /// carry neither `Cache-Control: max-age` nor `Expires`
+    #[serde(default = "default_response_cache_ttl_secs")]
+    pub response_cache_default_ttl_secs: u64,
+
+    /// Maximum body size, in bytes, that will be buffered into the response
+    /// cache; larger responses are forwarded unbuffered and never cached
+    #[serde(default = "default_response_cache_max_body_bytes")]
+    pub response_cache_max_body_bytes: usize,
+
+    /// Destinations that should receive a PROXY protocol header preserving
+    /// the original client address
+    #[serde(default)]
+    pub proxy_protocol: ProxyProtocolConfig,
+}
+
+fn default_response_cache_size() -> usize {
+    512
+}
+
+fn default_response_cache_ttl_secs() -> u64 {
+    300
+}
+
+fn default_response_cache_max_body_bytes() -> usize {
+    2 * 1024 * 1024
+}
+
+fn default_dns_cache_size() -> usize {
+    1024
+}
+
+fn default_mitm_cert_cache_size() -> usize {
+    256
 }
 
 impl Default for Config {
@@ -26,6 +177,18 @@ impl Default for Config {
             ],
             ton_gateway: "https://gateway.ton.org".to_string(),
             verbose_logging: false,
+            auth_basic_users: Vec::new(),
+            auth_bearer_tokens: Vec::new(),
+            upstream_proxy: None,
+            block_ton_https: false,
+            dns_cache_size: default_dns_cache_size(),
+            dns_static_hosts: HashMap::new(),
+            mitm_ca: None,
+            mitm_cert_cache_size: default_mitm_cert_cache_size(),
+            response_cache_size: default_response_cache_size(),
+            response_cache_default_ttl_secs: default_response_cache_ttl_secs(),
+            response_cache_max_body_bytes: default_response_cache_max_body_bytes(),
+            proxy_protocol: ProxyProtocolConfig::default(),
         }
     }
 }
@@ -51,4 +214,41 @@ impl Config {
     pub fn is_ton_domain(&self, domain: &str) -> bool {
         self.ton_domains.iter().any(|d| domain.ends_with(d))
     }
+
+    /// Whether proxy authentication is required before forwarding a request
+    pub fn auth_required(&self) -> bool {
+        !self.auth_basic_users.is_empty() || !self.auth_bearer_tokens.is_empty()
+    }
+
+    /// Check a decoded Basic `username:password` pair against the configured
+    /// users. Compares in constant time so an exposed proxy can't have its
+    /// credentials recovered byte-by-byte via a timing side-channel.
+    pub fn check_basic_auth(&self, username: &str, password: &str) -> bool {
+        self.auth_basic_users.iter().any(|u| {
+            let username_matches = u.username.as_bytes().ct_eq(username.as_bytes());
+            let password_matches = u.password.as_bytes().ct_eq(password.as_bytes());
+            (username_matches & password_matches).into()
+        })
+    }
+
+    /// Check a bearer token against the configured tokens, in constant time (see `check_basic_auth`)
+    pub fn check_bearer_auth(&self, token: &str) -> bool {
+        self.auth_bearer_tokens
+            .iter()
+            .any(|t| bool::from(t.as_bytes().ct_eq(token.as_bytes())))
+    }
+
+    /// Resolve `ton_gateway` down to a `host:port` authority to dial directly,
+    /// for routing SNI-matched HTTPS `CONNECT` tunnels there
+    pub fn ton_gateway_authority(&self) -> Result<String> {
+        let uri: Uri = self.ton_gateway.parse()?;
+        let host = uri
+            .host()
+            .ok_or_else(|| anyhow!("ton_gateway '{}' has no host", self.ton_gateway))?;
+        let port = uri.port_u16().unwrap_or(match uri.scheme_str() {
+            Some("http") => 80,
+            _ => 443,
+        });
+        Ok(format!("{}:{}", host, port))
+    }
 }
\ No newline at end of file
@@ -0,0 +1,146 @@
+//! A caching DNS resolver used for every outbound connection the proxy opens
+//! itself, so repeated requests to the same origin skip redundant system
+//! resolution.
+//!
+//! Lookups go through `trust-dns-resolver` and are kept in an LRU cache
+//! bounded by `Config::dns_cache_size`, keyed by hostname and honoring each
+//! answer's DNS TTL; entries are re-resolved once they expire. Static
+//! overrides from `Config::dns_static_hosts` are served straight from the map.
+
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::num::NonZeroUsize;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use anyhow::{bail, Context, Result};
+use lru::LruCache;
+use tokio::net::TcpStream;
+use trust_dns_resolver::TokioAsyncResolver;
+
+use crate::config::Config;
+
+struct CacheEntry {
+    ips: Vec<IpAddr>,
+    expires_at: Instant,
+}
+
+pub struct Resolver {
+    resolver: TokioAsyncResolver,
+    cache: Mutex<LruCache<String, CacheEntry>>,
+    static_hosts: HashMap<String, IpAddr>,
+}
+
+impl Resolver {
+    /// Build a resolver using the host's system DNS configuration (`/etc/resolv.conf` on Unix)
+    pub fn new(config: &Config) -> Result<Self> {
+        let resolver = TokioAsyncResolver::tokio_from_system_conf()
+            .context("failed to initialize DNS resolver from system configuration")?;
+        let capacity = NonZeroUsize::new(config.dns_cache_size.max(1))
+            .expect("dns_cache_size.max(1) is never zero");
+
+        Ok(Self {
+            resolver,
+            cache: Mutex::new(LruCache::new(capacity)),
+            static_hosts: config.dns_static_hosts.clone(),
+        })
+    }
+
+    /// Resolve `host` to its IP addresses, preferring (in order) a static
+    /// override, an already-valid IP literal, the cache, and finally a live
+    /// DNS lookup, which repopulates the cache with the answer's TTL.
+    pub async fn resolve(&self, host: &str) -> Result<Vec<IpAddr>> {
+        if let Some(ip) = self.static_hosts.get(host) {
+            return Ok(vec![*ip]);
+        }
+
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            return Ok(vec![ip]);
+        }
+
+        if let Some(ips) = self.cached(host) {
+            return Ok(ips);
+        }
+
+        let lookup = self
+            .resolver
+            .lookup_ip(host)
+            .await
+            .with_context(|| format!("failed to resolve {}", host))?;
+        let expires_at = lookup.valid_until();
+        let ips: Vec<IpAddr> = lookup.iter().collect();
+        if ips.is_empty() {
+            bail!("DNS lookup for {} returned no addresses", host);
+        }
+
+        self.cache.lock().unwrap().put(
+            host.to_string(),
+            CacheEntry { ips: ips.clone(), expires_at },
+        );
+
+        Ok(ips)
+    }
+
+    fn cached(&self, host: &str) -> Option<Vec<IpAddr>> {
+        let mut cache = self.cache.lock().unwrap();
+        let entry = cache.get(host)?;
+        if entry.expires_at <= Instant::now() {
+            cache.pop(host);
+            return None;
+        }
+        Some(entry.ips.clone())
+    }
+
+    /// Resolve the host in a `host:port` authority through this cache and
+    /// connect to its first address. The single place `tunnel()` and the
+    /// regular client path dial a `TcpStream` from.
+    pub async fn connect(&self, host_port: &str) -> io::Result<TcpStream> {
+        let (host, port) = split_host_port(host_port)?;
+        let ips = self
+            .resolve(&host)
+            .await
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        let addr = SocketAddr::new(ips[0], port);
+        TcpStream::connect(addr).await
+    }
+}
+
+/// Split a `host:port` authority into its parts, stripping the brackets off a
+/// bracketed IPv6 literal (`[::1]:443` -> `::1`, `443`) so the host half is
+/// usable directly with `IpAddr::parse`/`resolve`.
+fn split_host_port(host_port: &str) -> io::Result<(String, u16)> {
+    let (host, port) = host_port.rsplit_once(':').ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidInput, format!("'{}' is not a host:port", host_port))
+    })?;
+    let port: u16 = port
+        .parse()
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, format!("invalid port in '{}'", host_port)))?;
+    let host = host.strip_prefix('[').and_then(|h| h.strip_suffix(']')).unwrap_or(host);
+    Ok((host.to_string(), port))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_unbracketed_host_port() {
+        assert_eq!(split_host_port("example.com:443").unwrap(), ("example.com".to_string(), 443));
+    }
+
+    #[test]
+    fn strips_brackets_from_ipv6_host_port() {
+        assert_eq!(split_host_port("[::1]:443").unwrap(), ("::1".to_string(), 443));
+    }
+
+    #[test]
+    fn rejects_missing_port() {
+        assert!(split_host_port("example.com").is_err());
+    }
+
+    #[test]
+    fn rejects_non_numeric_port() {
+        assert!(split_host_port("example.com:https").is_err());
+    }
+}
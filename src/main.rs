@@ -1,10 +1,17 @@
+mod cache;
 mod config;
+mod dns;
+mod mitm;
+mod proxy_protocol;
+mod sni;
+mod upstream;
 
 use anyhow::{Context, Result, anyhow};
+use base64::Engine;
 use clap::Parser;
 use config::Config;
 use futures::future::try_join;
-use http::{Request, Response, StatusCode, Uri, Method};
+use http::{HeaderMap, HeaderValue, Request, Response, StatusCode, Uri, Method, header};
 use hyper::service::{make_service_fn, service_fn};
 use hyper::upgrade::Upgraded;
 use hyper::{Body, Client, Server};
@@ -15,7 +22,14 @@ use std::net::SocketAddr;
 use std::path::PathBuf;
 use std::sync::Arc;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
+use upstream::UpstreamConnector;
+
+tokio::task_local! {
+    /// Address of the client whose request is currently being served, made
+    /// available to `UpstreamConnector` (which only gets a `Uri` from hyper)
+    /// so it can emit a PROXY protocol header when connecting on its behalf.
+    pub(crate) static CLIENT_ADDR: SocketAddr;
+}
 
 /// Command line arguments for the proxy server
 #[derive(Parser, Debug)]
@@ -39,9 +53,13 @@ struct Args {
 }
 
 /// Application state shared across request handlers
-struct AppState {
-    client: Client<HttpsConnector<hyper::client::HttpConnector>>,
-    config: Config,
+pub(crate) struct AppState {
+    pub(crate) client: Client<HttpsConnector<UpstreamConnector>>,
+    pub(crate) config: Config,
+    pub(crate) resolver: Arc<dns::Resolver>,
+    pub(crate) mitm_ca: Option<Arc<mitm::CertAuthority>>,
+    pub(crate) response_cache: cache::ResponseCache,
+    pub(crate) proxy_protocol: proxy_protocol::Settings,
 }
 
 #[tokio::main]
@@ -98,20 +116,42 @@ async fn main() -> Result<()> {
         config.verbose_logging = true;
     }
 
-    // Create a client with TLS support for HTTPS requests
-    let https = HttpsConnector::new();
-    let client = Client::builder().build::<_, Body>(https);
+    // Caching DNS resolver shared by the regular client path and tunnel()
+    let resolver = Arc::new(dns::Resolver::new(&config).context("Failed to initialize DNS resolver")?);
+
+    // Root CA used to intercept HTTPS CONNECTs to TON domains, if configured
+    let mitm_ca = mitm::CertAuthority::load(&config).context("Failed to load MITM CA")?;
+
+    // Cache of TON gateway responses, consulted before re-fetching unchanged content
+    let response_cache = cache::ResponseCache::new(&config);
+
+    // Which outbound connections should carry a PROXY protocol header
+    let proxy_protocol = proxy_protocol::Settings::from_config(&config);
+
+    // Create a client with TLS support for HTTPS requests, routed through the
+    // configured upstream proxy (if any) instead of dialing origins directly
+    let connector = UpstreamConnector::new(config.upstream_proxy.clone(), Arc::clone(&resolver), proxy_protocol.clone());
+    let https = HttpsConnector::new_with_connector(connector);
+    let mut client_builder = Client::builder();
+    if proxy_protocol.requires_unpooled_connections() {
+        // The PROXY protocol header is written once, when UpstreamConnector
+        // dials a new TCP connection; pooling would let it keep attributing
+        // every later request on a reused connection to the first client.
+        client_builder.pool_max_idle_per_host(0);
+    }
+    let client = client_builder.build::<_, Body>(https);
 
     // Create application state
-    let state = Arc::new(AppState { client, config });
+    let state = Arc::new(AppState { client, config, resolver, mitm_ca, response_cache, proxy_protocol });
 
     // Create a service function that will handle incoming requests
-    let make_svc = make_service_fn(move |_conn| {
+    let make_svc = make_service_fn(move |conn: &hyper::server::conn::AddrStream| {
         let state = Arc::clone(&state);
+        let client_addr = conn.remote_addr();
         async move {
             Ok::<_, Infallible>(service_fn(move |req| {
                 let state = Arc::clone(&state);
-                async move { handle_request(state, req).await }
+                CLIENT_ADDR.scope(client_addr, handle_request(state, client_addr, req))
             }))
         }
     });
@@ -130,15 +170,22 @@ async fn main() -> Result<()> {
 /// Main request handler that dispatches to appropriate handlers based on request method
 async fn handle_request(
     state: Arc<AppState>,
+    client_addr: SocketAddr,
     req: Request<Body>,
 ) -> Result<Response<Body>, Infallible> {
     if state.config.verbose_logging {
         debug!("Received request: {} {}", req.method(), req.uri());
     }
-    
+
+    // Require Proxy-Authorization before forwarding anything, CONNECT included
+    if !check_proxy_auth(&state.config, req.headers()) {
+        warn!("Rejected {} {} due to missing or invalid proxy authentication", req.method(), req.uri());
+        return Ok(proxy_auth_required_response());
+    }
+
     // Handle CONNECT method differently for HTTPS tunneling
     if req.method() == Method::CONNECT {
-        match handle_connect(req).await {
+        match handle_connect(Arc::clone(&state), client_addr, req).await {
             Ok(response) => Ok(response),
             Err(e) => {
                 error!("CONNECT error: {}", e);
@@ -161,25 +208,82 @@ async fn handle_request(
     }
 }
 
+/// Check the `Proxy-Authorization` header against the configured credentials.
+/// Returns `true` when authentication is disabled (no users/tokens configured)
+/// or the header carries a valid Basic or Bearer credential.
+fn check_proxy_auth(config: &Config, headers: &HeaderMap) -> bool {
+    if !config.auth_required() {
+        return true;
+    }
+
+    let value = match headers.get(header::PROXY_AUTHORIZATION).and_then(|h| h.to_str().ok()) {
+        Some(v) => v,
+        None => return false,
+    };
+
+    if let Some(encoded) = value.strip_prefix("Basic ") {
+        let decoded = match base64::engine::general_purpose::STANDARD.decode(encoded) {
+            Ok(d) => d,
+            Err(_) => return false,
+        };
+        let decoded = match String::from_utf8(decoded) {
+            Ok(d) => d,
+            Err(_) => return false,
+        };
+        return match decoded.split_once(':') {
+            Some((user, pass)) => config.check_basic_auth(user, pass),
+            None => false,
+        };
+    }
+
+    if let Some(token) = value.strip_prefix("Bearer ") {
+        return config.check_bearer_auth(token);
+    }
+
+    false
+}
+
+/// Build the `407 Proxy Authentication Required` response sent for missing or invalid credentials
+fn proxy_auth_required_response() -> Response<Body> {
+    let mut response = Response::new(Body::from("Proxy Authentication Required"));
+    *response.status_mut() = StatusCode::PROXY_AUTHENTICATION_REQUIRED;
+    response.headers_mut().insert(
+        header::PROXY_AUTHENTICATE,
+        HeaderValue::from_static("Basic realm=\"TON Gateway Proxy\", Bearer"),
+    );
+    response
+}
+
 /// Handle HTTPS CONNECT requests by establishing a tunnel
-async fn handle_connect(req: Request<Body>) -> Result<Response<Body>> {
+async fn handle_connect(state: Arc<AppState>, client_addr: SocketAddr, req: Request<Body>) -> Result<Response<Body>> {
     // Extract the target address from the request URI
     let uri = req.uri();
-    let addr = uri.authority()
+    let authority = uri.authority()
         .ok_or_else(|| anyhow!("CONNECT request missing authority"))?
-        .to_string();
-    
+        .clone();
+    let addr = authority.to_string();
+
     info!("CONNECT request to {}", addr);
-    
+
+    // Intercept (MITM) TON domains when a CA is configured, so TON rewriting
+    // can apply inside HTTPS too; everything else stays an opaque tunnel
+    let intercept = state.mitm_ca.is_some() && state.config.is_ton_domain(authority.host());
+
     // Create a response that will be upgraded
     let mut response = Response::new(Body::empty());
     *response.status_mut() = StatusCode::OK;
-    
+
     // Spawn a task to handle the tunnel after the response is sent
     tokio::task::spawn(async move {
         match hyper::upgrade::on(req).await {
             Ok(upgraded) => {
-                if let Err(e) = tunnel(upgraded, addr).await {
+                let result = if intercept {
+                    let ca = state.mitm_ca.clone().expect("checked above");
+                    mitm::intercept(Arc::clone(&state), ca, client_addr, upgraded).await
+                } else {
+                    tunnel(state, client_addr, upgraded, addr).await
+                };
+                if let Err(e) = result {
                     error!("Tunnel error: {}", e);
                 }
             }
@@ -188,15 +292,46 @@ async fn handle_connect(req: Request<Body>) -> Result<Response<Body>> {
             }
         }
     });
-    
+
     Ok(response)
 }
 
 /// Create a tunnel between the client and the target server
-async fn tunnel(mut upgraded: Upgraded, addr: String) -> Result<()> {
-    // Connect to the target server
-    let mut server = TcpStream::connect(addr).await?;
-    
+async fn tunnel(state: Arc<AppState>, client_addr: SocketAddr, mut upgraded: Upgraded, addr: String) -> Result<()> {
+    // Peek at the client's first bytes: if this is a TLS ClientHello, its SNI
+    // tells us the real hostname even when the CONNECT authority is an IP
+    let (buffered, sni_result) = sni::peek_sni(&mut upgraded).await?;
+
+    let target = match &sni_result {
+        sni::SniResult::Found(hostname) if state.config.is_ton_domain(hostname) => {
+            if state.config.block_ton_https {
+                info!("Blocking HTTPS CONNECT to TON domain {} (via SNI)", hostname);
+                return Ok(());
+            }
+            info!("Routing HTTPS CONNECT to TON domain {} (via SNI) through TON gateway", hostname);
+            state.config.ton_gateway_authority()?
+        }
+        _ => addr,
+    };
+
+    // Connect to the target server, through the upstream proxy if one is configured
+    let mut server = upstream::connect(state.config.upstream_proxy.as_ref(), &target, &state.resolver).await?;
+
+    // Let the backend see the original client address, if configured to for this destination
+    if let Some(host) = target.rsplit_once(':').map(|(host, _)| host) {
+        if state.proxy_protocol.applies_to(host) {
+            if let Ok(destination) = server.peer_addr() {
+                let header = proxy_protocol::header(state.proxy_protocol.version, client_addr, destination);
+                server.write_all(&header).await?;
+            }
+        }
+    }
+
+    // Replay the bytes already read off the client (e.g. the buffered ClientHello)
+    if !buffered.is_empty() {
+        server.write_all(&buffered).await?;
+    }
+
     // Create bidirectional streams
     let (mut client_read, mut client_write) = tokio::io::split(upgraded);
     let (mut server_read, mut server_write) = server.split();
@@ -274,24 +409,30 @@ async fn proxy_internal(
     
     if is_ton_domain {
         info!("Handling TON domain request: {}", uri);
-        
+
         // Modify the request to go through the TON gateway
         let new_uri = rewrite_ton_uri(&uri, &state.config.ton_gateway)?;
-        
+
         if state.config.verbose_logging {
             debug!("Rewritten URI: {}", new_uri);
         }
-        
+
+        let method = req.method().clone();
+        if let Some(cached) = state.response_cache.get(&method, &new_uri) {
+            info!("Serving cached TON gateway response: {}", new_uri);
+            return Ok(cached);
+        }
+
         // Create a new request with the rewritten URI
         let (mut parts, body) = req.into_parts();
-        parts.uri = new_uri;
+        parts.uri = new_uri.clone();
         let new_req = Request::from_parts(parts, body);
-        
+
         // Forward the request to the TON gateway
         match state.client.request(new_req).await {
             Ok(response) => {
                 info!("TON gateway response status: {}", response.status());
-                Ok(response)
+                Ok(state.response_cache.store(&method, &new_uri, response).await?)
             }
             Err(e) => {
                 error!("TON gateway request failed: {}", e);
@@ -329,7 +470,7 @@ async fn proxy_internal(
 }
 
 /// Rewrite a URI to go through the TON gateway
-fn rewrite_ton_uri(uri: &Uri, gateway: &str) -> Result<Uri> {
+pub(crate) fn rewrite_ton_uri(uri: &Uri, gateway: &str) -> Result<Uri> {
     let host = uri.host().unwrap_or("");
     let path = uri.path();
     let query = uri.query().map(|q| format!("?{}", q)).unwrap_or_default();
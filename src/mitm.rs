@@ -0,0 +1,240 @@
+//! TLS termination ("MITM") for `CONNECT` tunnels to TON domains.
+//!
+//! Plain `tunnel()` only ever sees ciphertext, so `.ton`/`t.me` sites served
+//! over HTTPS can never be rewritten to `ton_gateway`. When `Config::mitm_ca`
+//! is set, `handle_connect` instead terminates TLS toward the client itself
+//! using a leaf certificate minted on demand and signed by the configured
+//! root CA (cached by SNI), decrypts the request, rewrites it with
+//! `rewrite_ton_uri` exactly like plaintext HTTP, and makes the outbound
+//! HTTPS call on the proxy's behalf. Non-TON CONNECTs are left alone and
+//! keep going through the opaque `tunnel()` byte-splice.
+
+use std::net::SocketAddr;
+use std::num::NonZeroUsize;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, Context, Result};
+use http::{Request, Response, StatusCode};
+use hyper::server::conn::Http;
+use hyper::service::service_fn;
+use hyper::upgrade::Upgraded;
+use hyper::Body;
+use log::error;
+use lru::LruCache;
+use rcgen::{Certificate, CertificateParams, DistinguishedName, DnType, KeyPair};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use rustls::{Certificate as RustlsCertificate, PrivateKey, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+
+use crate::config::Config;
+use crate::{AppState, CLIENT_ADDR};
+
+/// A loaded root CA plus an on-demand, SNI-keyed, LRU-bounded cache of the
+/// leaf certificates it has signed so far. `config` is kept around so
+/// `resolve()` can re-check `is_ton_domain` against the SNI it's actually
+/// asked to serve, rather than trusting that only TON CONNECTs reach here.
+pub struct CertAuthority {
+    ca_cert: Certificate,
+    ca_cert_der: Vec<u8>,
+    leaves: Mutex<LruCache<String, Arc<CertifiedKey>>>,
+    config: Config,
+}
+
+impl CertAuthority {
+    /// Load the CA from `Config::mitm_ca`, or return `None` if MITM interception is disabled
+    pub fn load(config: &Config) -> Result<Option<Arc<Self>>> {
+        let ca = match &config.mitm_ca {
+            Some(ca) => ca,
+            None => return Ok(None),
+        };
+
+        let cert_pem = std::fs::read_to_string(&ca.cert_path)
+            .with_context(|| format!("failed to read CA certificate {}", ca.cert_path.display()))?;
+        let key_pem = std::fs::read_to_string(&ca.key_path)
+            .with_context(|| format!("failed to read CA private key {}", ca.key_path.display()))?;
+
+        let key_pair = KeyPair::from_pem(&key_pem).context("failed to parse CA private key")?;
+        let params = CertificateParams::from_ca_cert_pem(&cert_pem, key_pair)
+            .context("failed to parse CA certificate")?;
+        let ca_cert = Certificate::from_params(params).context("failed to load CA certificate")?;
+        let ca_cert_der = ca_cert.serialize_der().context("failed to serialize CA certificate")?;
+
+        let capacity = NonZeroUsize::new(config.mitm_cert_cache_size.max(1))
+            .expect("mitm_cert_cache_size.max(1) is never zero");
+
+        Ok(Some(Arc::new(Self {
+            ca_cert,
+            ca_cert_der,
+            leaves: Mutex::new(LruCache::new(capacity)),
+            config: config.clone(),
+        })))
+    }
+
+    /// Get or mint a leaf certificate for `hostname`, signed by the loaded CA
+    fn leaf_for(&self, hostname: &str) -> Result<Arc<CertifiedKey>> {
+        if let Some(key) = self.leaves.lock().unwrap().get(hostname) {
+            return Ok(Arc::clone(key));
+        }
+
+        let mut params = CertificateParams::new(vec![hostname.to_string()]);
+        let mut dn = DistinguishedName::new();
+        dn.push(DnType::CommonName, hostname);
+        params.distinguished_name = dn;
+
+        let leaf = Certificate::from_params(params).context("failed to generate leaf certificate")?;
+        let leaf_der = leaf
+            .serialize_der_with_signer(&self.ca_cert)
+            .context("failed to sign leaf certificate")?;
+        let leaf_key_der = leaf.serialize_private_key_der();
+
+        let chain = vec![RustlsCertificate(leaf_der), RustlsCertificate(self.ca_cert_der.clone())];
+        let signing_key = rustls::sign::any_supported_type(&PrivateKey(leaf_key_der))
+            .map_err(|_| anyhow!("unsupported leaf private key type"))?;
+        let certified_key = Arc::new(CertifiedKey::new(chain, signing_key));
+
+        self.leaves
+            .lock()
+            .unwrap()
+            .put(hostname.to_string(), Arc::clone(&certified_key));
+        Ok(certified_key)
+    }
+
+    /// The logic behind `resolve()`, taking a plain `Option<&str>` instead of
+    /// rustls's `ClientHello` (whose constructor isn't public) so it can be
+    /// unit tested directly.
+    fn resolve_hostname(&self, hostname: Option<&str>) -> Option<Arc<CertifiedKey>> {
+        let hostname = hostname?;
+        // `handle_connect` only intercepts TON-domain CONNECTs, but the SNI
+        // seen here comes straight from the client and may not match the
+        // authority that was checked: refuse to mint (and cache) a leaf for
+        // anything else rather than letting the CA sign for an arbitrary host.
+        if !self.config.is_ton_domain(hostname) {
+            return None;
+        }
+        self.leaf_for(hostname).ok()
+    }
+}
+
+impl ResolvesServerCert for CertAuthority {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        self.resolve_hostname(client_hello.server_name())
+    }
+}
+
+/// Terminate TLS on `upgraded` using a certificate signed by `ca`, decrypt
+/// requests, rewrite TON URIs and forward them through `state.client` - the
+/// same logic `proxy_internal` applies to plaintext TON requests.
+///
+/// `client_addr` is threaded through explicitly, like `tunnel()` already
+/// does, rather than read back out of the `CLIENT_ADDR` task-local: this
+/// whole call tree runs inside a task spawned by `handle_connect`, and
+/// task-locals don't propagate across a `tokio::task::spawn` boundary.
+pub async fn intercept(state: Arc<AppState>, ca: Arc<CertAuthority>, client_addr: SocketAddr, upgraded: Upgraded) -> Result<()> {
+    let tls_config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_cert_resolver(ca);
+    let acceptor = TlsAcceptor::from(Arc::new(tls_config));
+    let tls_stream = acceptor.accept(upgraded).await.context("TLS handshake with client failed")?;
+
+    let service = service_fn(move |req: Request<Body>| {
+        let state = Arc::clone(&state);
+        async move { Ok::<_, std::convert::Infallible>(handle_intercepted_request(state, client_addr, req).await) }
+    });
+
+    Http::new()
+        .serve_connection(tls_stream, service)
+        .await
+        .context("intercepted HTTPS connection error")?;
+
+    Ok(())
+}
+
+async fn handle_intercepted_request(state: Arc<AppState>, client_addr: SocketAddr, req: Request<Body>) -> Response<Body> {
+    match forward_intercepted_request(state, client_addr, req).await {
+        Ok(response) => response,
+        Err(e) => {
+            error!("Intercepted TON request failed: {}", e);
+            let mut response = Response::new(Body::from(format!("Proxy error: {}", e)));
+            *response.status_mut() = StatusCode::BAD_GATEWAY;
+            response
+        }
+    }
+}
+
+/// Reconstruct the absolute `https://` URI the decrypted request was headed
+/// to (from its `Host` header, since the request line only carries the
+/// path), then rewrite it with `rewrite_ton_uri` and forward it through
+/// `state.client` as `proxy_internal` does. The response cache is neither
+/// consulted nor populated here.
+async fn forward_intercepted_request(state: Arc<AppState>, client_addr: SocketAddr, req: Request<Body>) -> Result<Response<Body>> {
+    let host = req
+        .headers()
+        .get(http::header::HOST)
+        .and_then(|h| h.to_str().ok())
+        .ok_or_else(|| anyhow!("intercepted request missing Host header"))?
+        .to_string();
+
+    let path_and_query = req.uri().path_and_query().map(|p| p.as_str()).unwrap_or("/");
+    let full_uri: http::Uri = format!("https://{}{}", host, path_and_query)
+        .parse()
+        .context("failed to reconstruct intercepted request URI")?;
+
+    let new_uri = crate::rewrite_ton_uri(&full_uri, &state.config.ton_gateway)?;
+    let (mut parts, body) = req.into_parts();
+    parts.uri = new_uri;
+    let new_req = Request::from_parts(parts, body);
+
+    // Re-establish the scope `UpstreamConnector::call` reads `CLIENT_ADDR`
+    // from: it was lost when `handle_connect` spawned the task this call
+    // tree is running in, so `client_addr` is restored here from the
+    // explicit parameter instead of the (absent) ambient task-local.
+    CLIENT_ADDR
+        .scope(client_addr, state.client.request(new_req))
+        .await
+        .context("TON gateway request failed")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rcgen::{BasicConstraints, IsCa};
+
+    /// A `CertAuthority` backed by a throwaway self-signed CA, for exercising
+    /// `resolve_hostname` without touching the filesystem via `load()`.
+    fn test_ca(ton_domains: Vec<&str>) -> CertAuthority {
+        let mut params = CertificateParams::new(vec!["Test CA".to_string()]);
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca_cert = Certificate::from_params(params).expect("failed to generate test CA");
+        let ca_cert_der = ca_cert.serialize_der().expect("failed to serialize test CA");
+
+        let mut config = Config::default();
+        config.ton_domains = ton_domains.into_iter().map(String::from).collect();
+
+        CertAuthority {
+            ca_cert,
+            ca_cert_der,
+            leaves: Mutex::new(LruCache::new(NonZeroUsize::new(8).unwrap())),
+            config,
+        }
+    }
+
+    #[test]
+    fn refuses_to_mint_for_non_ton_sni() {
+        let ca = test_ca(vec!["ton", "t.me"]);
+        assert!(ca.resolve_hostname(Some("evil.example.com")).is_none());
+    }
+
+    #[test]
+    fn mints_for_ton_sni() {
+        let ca = test_ca(vec!["ton", "t.me"]);
+        assert!(ca.resolve_hostname(Some("gateway.ton")).is_some());
+    }
+
+    #[test]
+    fn refuses_when_sni_absent() {
+        let ca = test_ca(vec!["ton"]);
+        assert!(ca.resolve_hostname(None).is_none());
+    }
+}
@@ -0,0 +1,165 @@
+//! PROXY protocol (v1 text, v2 binary) header construction.
+//!
+//! Backends behind this proxy normally only ever see the proxy's own source
+//! address. When enabled for a destination, `tunnel()` and the TON gateway
+//! connector prepend one of these headers to the outbound connection so the
+//! backend can recover the original client address instead.
+
+use std::net::SocketAddr;
+
+use http::Uri;
+
+use crate::config::{Config, ProxyProtocolVersion};
+
+/// Build the header to write before any other bytes sent to `destination`,
+/// identifying `client` as the original source of the connection.
+pub fn header(version: ProxyProtocolVersion, client: SocketAddr, destination: SocketAddr) -> Vec<u8> {
+    match version {
+        ProxyProtocolVersion::V1 => v1_header(client, destination),
+        ProxyProtocolVersion::V2 => v2_header(client, destination),
+    }
+}
+
+fn v1_header(client: SocketAddr, destination: SocketAddr) -> Vec<u8> {
+    match (client, destination) {
+        (SocketAddr::V4(src), SocketAddr::V4(dst)) => {
+            format!("PROXY TCP4 {} {} {} {}\r\n", src.ip(), dst.ip(), src.port(), dst.port()).into_bytes()
+        }
+        (SocketAddr::V6(src), SocketAddr::V6(dst)) => {
+            format!("PROXY TCP6 {} {} {} {}\r\n", src.ip(), dst.ip(), src.port(), dst.port()).into_bytes()
+        }
+        _ => b"PROXY UNKNOWN\r\n".to_vec(),
+    }
+}
+
+const V2_SIGNATURE: [u8; 12] = [0x0D, 0x0A, 0x0D, 0x0A, 0x00, 0x0D, 0x0A, 0x51, 0x55, 0x49, 0x54, 0x0A];
+
+fn v2_header(client: SocketAddr, destination: SocketAddr) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(28);
+    buf.extend_from_slice(&V2_SIGNATURE);
+    buf.push(0x21); // version 2, command PROXY
+
+    match (client, destination) {
+        (SocketAddr::V4(src), SocketAddr::V4(dst)) => {
+            buf.push(0x11); // AF_INET, SOCK_STREAM
+            buf.extend_from_slice(&12u16.to_be_bytes());
+            buf.extend_from_slice(&src.ip().octets());
+            buf.extend_from_slice(&dst.ip().octets());
+            buf.extend_from_slice(&src.port().to_be_bytes());
+            buf.extend_from_slice(&dst.port().to_be_bytes());
+        }
+        (SocketAddr::V6(src), SocketAddr::V6(dst)) => {
+            buf.push(0x21); // AF_INET6, SOCK_STREAM
+            buf.extend_from_slice(&36u16.to_be_bytes());
+            buf.extend_from_slice(&src.ip().octets());
+            buf.extend_from_slice(&dst.ip().octets());
+            buf.extend_from_slice(&src.port().to_be_bytes());
+            buf.extend_from_slice(&dst.port().to_be_bytes());
+        }
+        _ => {
+            buf.push(0x00); // AF_UNSPEC
+            buf.extend_from_slice(&0u16.to_be_bytes());
+        }
+    }
+
+    buf
+}
+
+/// Which outbound connections should carry a PROXY protocol header, resolved
+/// once from `Config` so `UpstreamConnector` doesn't need the whole config.
+#[derive(Clone)]
+pub struct Settings {
+    pub version: ProxyProtocolVersion,
+    ton_gateway_host: Option<String>,
+    domains: Vec<String>,
+}
+
+impl Settings {
+    pub fn from_config(config: &Config) -> Self {
+        let ton_gateway_host = if config.proxy_protocol.for_ton_gateway {
+            config.ton_gateway.parse::<Uri>().ok().and_then(|u| u.host().map(str::to_string))
+        } else {
+            None
+        };
+
+        Self {
+            version: config.proxy_protocol.version,
+            ton_gateway_host,
+            domains: config.proxy_protocol.for_domains.clone(),
+        }
+    }
+
+    /// Whether a connection to `host` should carry a PROXY protocol header
+    pub fn applies_to(&self, host: &str) -> bool {
+        self.ton_gateway_host.as_deref() == Some(host) || self.domains.iter().any(|d| host.ends_with(d.as_str()))
+    }
+
+    /// Whether any connection `AppState::client` makes could carry a PROXY
+    /// protocol header - the TON gateway, or any `for_domains` destination
+    /// reached via plain (non-TON) proxying. `UpstreamConnector` only writes
+    /// the header when it dials a fresh TCP connection, so `AppState::client`
+    /// must not be allowed to pool and reuse connections to any such
+    /// destination - reusing one would keep replaying the first request's
+    /// header for every later client.
+    pub fn requires_unpooled_connections(&self) -> bool {
+        self.ton_gateway_host.is_some() || !self.domains.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    #[test]
+    fn v1_header_ipv4() {
+        let client = SocketAddr::new(Ipv4Addr::new(203, 0, 113, 5).into(), 51413);
+        let destination = SocketAddr::new(Ipv4Addr::new(198, 51, 100, 7).into(), 443);
+        let header = v1_header(client, destination);
+        assert_eq!(header, b"PROXY TCP4 203.0.113.5 198.51.100.7 51413 443\r\n".to_vec());
+    }
+
+    #[test]
+    fn v1_header_ipv6() {
+        let client = SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 51413);
+        let destination = SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 443);
+        let header = v1_header(client, destination);
+        assert_eq!(header, b"PROXY TCP6 ::1 ::1 51413 443\r\n".to_vec());
+    }
+
+    #[test]
+    fn v1_header_mixed_families_is_unknown() {
+        let client = SocketAddr::new(Ipv4Addr::new(203, 0, 113, 5).into(), 51413);
+        let destination = SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 443);
+        let header = v1_header(client, destination);
+        assert_eq!(header, b"PROXY UNKNOWN\r\n".to_vec());
+    }
+
+    #[test]
+    fn v2_header_ipv4_layout() {
+        let client = SocketAddr::new(Ipv4Addr::new(203, 0, 113, 5).into(), 51413);
+        let destination = SocketAddr::new(Ipv4Addr::new(198, 51, 100, 7).into(), 443);
+        let header = v2_header(client, destination);
+
+        assert_eq!(header.len(), 28);
+        assert_eq!(&header[..12], &V2_SIGNATURE);
+        assert_eq!(header[12], 0x21);
+        assert_eq!(header[13], 0x11);
+        assert_eq!(&header[14..16], &12u16.to_be_bytes());
+        assert_eq!(&header[16..20], &[203, 0, 113, 5]);
+        assert_eq!(&header[20..24], &[198, 51, 100, 7]);
+        assert_eq!(&header[24..26], &51413u16.to_be_bytes());
+        assert_eq!(&header[26..28], &443u16.to_be_bytes());
+    }
+
+    #[test]
+    fn v2_header_mixed_families_is_af_unspec() {
+        let client = SocketAddr::new(Ipv4Addr::new(203, 0, 113, 5).into(), 51413);
+        let destination = SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 443);
+        let header = v2_header(client, destination);
+
+        assert_eq!(header.len(), 16);
+        assert_eq!(header[13], 0x00);
+        assert_eq!(&header[14..16], &0u16.to_be_bytes());
+    }
+}
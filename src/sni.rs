@@ -0,0 +1,242 @@
+//! Peeking at the SNI hostname of a TLS `ClientHello` without a TLS library.
+//!
+//! `handle_connect`/`tunnel` only see an opaque `CONNECT` authority, which is
+//! frequently an IP address rather than the real hostname the client is
+//! visiting. [`peek_sni`] buffers the first bytes the client sends after the
+//! tunnel is established and, if they form a `ClientHello`, extracts the
+//! `server_name` extension so `tunnel()` can apply `Config::is_ton_domain` to
+//! HTTPS traffic the same way `proxy_internal` already does for plaintext.
+
+use hyper::upgrade::Upgraded;
+use tokio::io::{self, AsyncReadExt};
+
+/// Stop buffering once this many bytes have been read without a complete
+/// ClientHello showing up; a real one is rarely more than a few KB.
+const MAX_PEEK_BYTES: usize = 16 * 1024;
+
+/// Outcome of attempting to parse a (possibly partial) buffer as a TLS `ClientHello`.
+#[derive(Debug, PartialEq, Eq)]
+pub enum SniResult {
+    /// The `server_name` extension was present and named a host
+    Found(String),
+    /// No SNI could be extracted: the ClientHello carried none, was malformed
+    /// or split across records, the handshake wasn't a ClientHello, or the
+    /// stream ended before a full record arrived
+    Absent,
+    /// The first byte isn't a TLS handshake record (`0x16`)
+    NotTls,
+    /// Not enough bytes have been buffered yet to know either way
+    Incomplete,
+}
+
+/// Read from `upgraded` until a full ClientHello has been buffered, the
+/// stream turns out not to be TLS, or `MAX_PEEK_BYTES` is exceeded. Returns
+/// every byte read, in order, so the caller can replay them onto the real
+/// upstream connection once the SNI has been inspected.
+pub async fn peek_sni(upgraded: &mut Upgraded) -> io::Result<(Vec<u8>, SniResult)> {
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; 4096];
+
+    loop {
+        match parse_client_hello_sni(&buf) {
+            SniResult::Incomplete if buf.len() < MAX_PEEK_BYTES => {
+                let n = upgraded.read(&mut chunk).await?;
+                if n == 0 {
+                    return Ok((buf, SniResult::Absent));
+                }
+                buf.extend_from_slice(&chunk[..n]);
+            }
+            result => return Ok((buf, result)),
+        }
+    }
+}
+
+/// Parse the SNI hostname out of a TLS record, per RFC 8446 / RFC 6066.
+///
+/// This only handles a ClientHello that fits in a single TLS record, which
+/// covers every ClientHello seen in practice; one split across records is
+/// treated as [`SniResult::Absent`] rather than reassembled.
+fn parse_client_hello_sni(buf: &[u8]) -> SniResult {
+    // TLS record header: content type (1), protocol version (2), length (2)
+    if buf.len() < 5 {
+        return SniResult::Incomplete;
+    }
+    if buf[0] != 0x16 {
+        return SniResult::NotTls;
+    }
+    let record_len = u16::from_be_bytes([buf[3], buf[4]]) as usize;
+    if buf.len() < 5 + record_len {
+        return SniResult::Incomplete;
+    }
+    let handshake = &buf[5..5 + record_len];
+
+    // Handshake header: msg type (1, must be ClientHello = 0x01), length (3)
+    if handshake.len() < 4 || handshake[0] != 0x01 {
+        return SniResult::Absent;
+    }
+    let hs_len = u32::from_be_bytes([0, handshake[1], handshake[2], handshake[3]]) as usize;
+    let body = &handshake[4..];
+    if body.len() < hs_len {
+        return SniResult::Absent;
+    }
+    let body = &body[..hs_len];
+
+    // client_version (2) + random (32)
+    let mut pos = 34;
+    if body.len() < pos + 1 {
+        return SniResult::Absent;
+    }
+
+    // session_id
+    let session_id_len = body[pos] as usize;
+    pos += 1 + session_id_len;
+    if body.len() < pos + 2 {
+        return SniResult::Absent;
+    }
+
+    // cipher_suites
+    let cipher_suites_len = u16::from_be_bytes([body[pos], body[pos + 1]]) as usize;
+    pos += 2 + cipher_suites_len;
+    if body.len() < pos + 1 {
+        return SniResult::Absent;
+    }
+
+    // compression_methods
+    let compression_len = body[pos] as usize;
+    pos += 1 + compression_len;
+    if body.len() < pos + 2 {
+        return SniResult::Absent;
+    }
+
+    // extensions
+    let extensions_len = u16::from_be_bytes([body[pos], body[pos + 1]]) as usize;
+    pos += 2;
+    if body.len() < pos + extensions_len {
+        return SniResult::Absent;
+    }
+    let mut extensions = &body[pos..pos + extensions_len];
+
+    while extensions.len() >= 4 {
+        let ext_type = u16::from_be_bytes([extensions[0], extensions[1]]);
+        let ext_len = u16::from_be_bytes([extensions[2], extensions[3]]) as usize;
+        if extensions.len() < 4 + ext_len {
+            return SniResult::Absent;
+        }
+        let ext_body = &extensions[4..4 + ext_len];
+
+        if ext_type == 0x0000 {
+            return match parse_server_name_extension(ext_body) {
+                Some(name) => SniResult::Found(name),
+                None => SniResult::Absent,
+            };
+        }
+
+        extensions = &extensions[4 + ext_len..];
+    }
+
+    SniResult::Absent
+}
+
+/// Parse the `server_name` extension body: a 2-byte list length, then
+/// 1-byte name type + 2-byte-prefixed name entries. Only `host_name` (`0x00`) is returned.
+fn parse_server_name_extension(body: &[u8]) -> Option<String> {
+    if body.len() < 2 {
+        return None;
+    }
+    let list_len = (u16::from_be_bytes([body[0], body[1]]) as usize).min(body.len() - 2);
+    let mut list = &body[2..2 + list_len];
+
+    while list.len() >= 3 {
+        let name_type = list[0];
+        let name_len = u16::from_be_bytes([list[1], list[2]]) as usize;
+        if list.len() < 3 + name_len {
+            return None;
+        }
+        let name = &list[3..3 + name_len];
+        if name_type == 0x00 {
+            return std::str::from_utf8(name).ok().map(str::to_string);
+        }
+        list = &list[3 + name_len..];
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Build a minimal single-record ClientHello carrying (or, if `hostname`
+    /// is `None`, omitting) a `server_name` extension, for exercising the
+    /// byte-offset parsing above without a real TLS stack.
+    fn client_hello(hostname: Option<&str>) -> Vec<u8> {
+        let mut extensions = Vec::new();
+        if let Some(hostname) = hostname {
+            let name = hostname.as_bytes();
+
+            let mut server_name_list = Vec::new();
+            server_name_list.push(0x00); // host_name
+            server_name_list.extend_from_slice(&(name.len() as u16).to_be_bytes());
+            server_name_list.extend_from_slice(name);
+
+            let mut sni_ext_body = Vec::new();
+            sni_ext_body.extend_from_slice(&(server_name_list.len() as u16).to_be_bytes());
+            sni_ext_body.extend_from_slice(&server_name_list);
+
+            extensions.extend_from_slice(&0x0000u16.to_be_bytes()); // extension type: server_name
+            extensions.extend_from_slice(&(sni_ext_body.len() as u16).to_be_bytes());
+            extensions.extend_from_slice(&sni_ext_body);
+        }
+
+        let mut body = Vec::new();
+        body.extend_from_slice(&[0x03, 0x03]); // client_version
+        body.extend_from_slice(&[0u8; 32]); // random
+        body.push(0); // session_id_len
+        body.extend_from_slice(&2u16.to_be_bytes()); // cipher_suites_len
+        body.extend_from_slice(&[0x13, 0x01]); // one cipher suite
+        body.push(1); // compression_methods_len
+        body.push(0); // compression method: null
+        body.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+        body.extend_from_slice(&extensions);
+
+        let mut handshake = Vec::new();
+        handshake.push(0x01); // ClientHello
+        handshake.extend_from_slice(&(body.len() as u32).to_be_bytes()[1..]); // 3-byte length
+        handshake.extend_from_slice(&body);
+
+        let mut record = Vec::new();
+        record.push(0x16); // handshake record
+        record.extend_from_slice(&[0x03, 0x01]); // legacy record version
+        record.extend_from_slice(&(handshake.len() as u16).to_be_bytes());
+        record.extend_from_slice(&handshake);
+        record
+    }
+
+    #[test]
+    fn finds_sni_hostname() {
+        let buf = client_hello(Some("gateway.ton"));
+        assert_eq!(parse_client_hello_sni(&buf), SniResult::Found("gateway.ton".to_string()));
+    }
+
+    #[test]
+    fn absent_when_no_sni_extension() {
+        let buf = client_hello(None);
+        assert_eq!(parse_client_hello_sni(&buf), SniResult::Absent);
+    }
+
+    #[test]
+    fn not_tls_for_non_handshake_byte() {
+        assert_eq!(parse_client_hello_sni(&[0x00, 0x00, 0x00, 0x00, 0x00]), SniResult::NotTls);
+    }
+
+    #[test]
+    fn incomplete_for_short_buffer() {
+        assert_eq!(parse_client_hello_sni(&[0x16, 0x03, 0x01]), SniResult::Incomplete);
+    }
+
+    #[test]
+    fn incomplete_when_record_truncated() {
+        let buf = client_hello(Some("gateway.ton"));
+        assert_eq!(parse_client_hello_sni(&buf[..buf.len() - 5]), SniResult::Incomplete);
+    }
+}
@@ -0,0 +1,208 @@
+//! Support for chaining proxied traffic through an upstream parent proxy.
+//!
+//! When `Config::upstream_proxy` is set, both plain HTTP requests and HTTPS
+//! `CONNECT` tunnels are forwarded to that parent proxy instead of dialing the
+//! origin server directly, but they don't get there the same way: HTTPS (and
+//! `tunnel()`'s raw splice) needs a CONNECT handshake first, while plain HTTP
+//! is forwarded by dialing the parent proxy directly and letting hyper write
+//! an absolute-form request line over that connection, as an upstream HTTP
+//! proxy expects. [`connect`] and [`connect_for_uri`] are the two places that
+//! know how to do this; [`UpstreamConnector`] adapts the latter to hyper's
+//! `Client` so the same logic backs `AppState::client`, while `tunnel()` in
+//! `main.rs` calls [`connect`] directly for its raw byte-splice.
+
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context as TaskContext, Poll};
+
+use http::Uri;
+use hyper::client::connect::{Connected, Connection};
+use hyper::service::Service;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::net::TcpStream;
+
+use crate::config::UpstreamProxy;
+use crate::dns::Resolver;
+use crate::proxy_protocol;
+use crate::CLIENT_ADDR;
+
+/// Open a connection to `target` (`host:port`) that will carry `connect`-style
+/// traffic (an HTTPS `CONNECT` tunnel or a request that will itself be
+/// wrapped in `CONNECT`), routing it through `upstream` with a CONNECT
+/// handshake if one is configured, or dialing `target` directly otherwise.
+/// Hostnames are resolved through `resolver`'s cache.
+///
+/// Plain HTTP requests must not go through this: with an upstream proxy
+/// configured, they're forwarded by dialing the proxy itself and letting
+/// hyper write the absolute-form request line, with no CONNECT handshake
+/// at all. See [`connect_for_uri`].
+pub async fn connect(upstream: Option<&UpstreamProxy>, target: &str, resolver: &Resolver) -> io::Result<TcpStream> {
+    match upstream {
+        Some(upstream) => connect_via_upstream(upstream, target, resolver).await,
+        None => resolver.connect(target).await,
+    }
+}
+
+/// Open the connection `UpstreamConnector` should return for `uri`: for
+/// `https` (TLS will be layered on top by `HttpsConnector`, and an HTTP/1.1
+/// `CONNECT` is the only way to get a parent proxy to pass encrypted bytes
+/// through), that's a CONNECT handshake to `upstream` like [`connect`]; for
+/// plain `http`, the parent proxy is dialed directly so hyper can send the
+/// absolute-form request line straight to it, matching what an upstream HTTP
+/// proxy expects for non-tunnelled traffic.
+pub async fn connect_for_uri(upstream: Option<&UpstreamProxy>, uri: &Uri, resolver: &Resolver) -> io::Result<TcpStream> {
+    let target = target_authority(uri)?;
+    match upstream {
+        Some(upstream) if uri.scheme_str() == Some("https") => connect_via_upstream(upstream, &target, resolver).await,
+        Some(upstream) => resolver.connect(&upstream.address).await.map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("failed to connect to upstream proxy {}: {}", upstream.address, e),
+            )
+        }),
+        None => resolver.connect(&target).await,
+    }
+}
+
+async fn connect_via_upstream(upstream: &UpstreamProxy, target: &str, resolver: &Resolver) -> io::Result<TcpStream> {
+    let mut stream = resolver.connect(&upstream.address).await.map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!("failed to connect to upstream proxy {}: {}", upstream.address, e),
+        )
+    })?;
+
+    let mut request = format!("CONNECT {target} HTTP/1.1\r\nHost: {target}\r\n");
+    if let Some(auth) = upstream.authorization_header() {
+        request.push_str(&format!("Proxy-Authorization: {}\r\n", auth));
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes()).await?;
+
+    let status_line = read_status_line(&mut stream).await?;
+    if !status_line.contains(" 200") {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("upstream proxy refused CONNECT to {}: {}", target, status_line.trim()),
+        ));
+    }
+
+    Ok(stream)
+}
+
+/// Read the upstream proxy's response headers up to the blank line and return the status line.
+/// The connection is then handed back untouched for use as the tunnel.
+async fn read_status_line(stream: &mut TcpStream) -> io::Result<String> {
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; 512];
+    loop {
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "upstream proxy closed connection during CONNECT"));
+        }
+        buf.extend_from_slice(&chunk[..n]);
+        if let Some(pos) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            let head = String::from_utf8_lossy(&buf[..pos]);
+            return Ok(head.lines().next().unwrap_or_default().to_string());
+        }
+        if buf.len() > 8192 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "upstream proxy response headers too large"));
+        }
+    }
+}
+
+/// Build the `host:port` authority to dial for a request URI, applying the scheme's default port.
+pub fn target_authority(uri: &Uri) -> io::Result<String> {
+    let host = uri
+        .host()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "URI is missing a host"))?;
+    let port = uri.port_u16().unwrap_or(match uri.scheme_str() {
+        Some("https") => 443,
+        _ => 80,
+    });
+    Ok(format!("{}:{}", host, port))
+}
+
+/// A connected `TcpStream`, possibly tunnelled through an upstream proxy, wearing
+/// the `Connection` impl hyper's `Client` needs from its connector's output type.
+/// The flag is set when the stream goes straight to an upstream proxy for a
+/// plain HTTP request, so hyper sends the absolute-form request line on it.
+pub struct UpstreamStream(TcpStream, bool);
+
+impl AsyncRead for UpstreamStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut TaskContext<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().0).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for UpstreamStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut TaskContext<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().0).poll_write(cx, buf)
+    }
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().0).poll_flush(cx)
+    }
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().0).poll_shutdown(cx)
+    }
+}
+
+impl Connection for UpstreamStream {
+    fn connected(&self) -> Connected {
+        Connected::new().proxy(self.1)
+    }
+}
+
+/// A hyper connector that dials origin servers through the configured upstream
+/// proxy (or directly, if none is configured). For plain HTTP requests through
+/// an upstream proxy the returned stream is marked as proxied, so hyper sends
+/// the absolute-form request line over it; for HTTPS,
+/// `hyper_tls::HttpsConnector` layers TLS on top of it.
+#[derive(Clone)]
+pub struct UpstreamConnector {
+    upstream: Option<UpstreamProxy>,
+    resolver: Arc<Resolver>,
+    proxy_protocol: proxy_protocol::Settings,
+}
+
+impl UpstreamConnector {
+    pub fn new(upstream: Option<UpstreamProxy>, resolver: Arc<Resolver>, proxy_protocol: proxy_protocol::Settings) -> Self {
+        Self { upstream, resolver, proxy_protocol }
+    }
+}
+
+impl Service<Uri> for UpstreamConnector {
+    type Response = UpstreamStream;
+    type Error = io::Error;
+    type Future = Pin<Box<dyn Future<Output = io::Result<Self::Response>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let upstream = self.upstream.clone();
+        let resolver = Arc::clone(&self.resolver);
+        let proxy_protocol = self.proxy_protocol.clone();
+        Box::pin(async move {
+            let mut stream = connect_for_uri(upstream.as_ref(), &uri, &resolver).await?;
+            let proxied = upstream.is_some() && uri.scheme_str() != Some("https");
+
+            let host = uri.host().unwrap_or_default();
+            if proxy_protocol.applies_to(host) {
+                // Ambient client address of the request currently being served,
+                // set by the `CLIENT_ADDR.scope(...)` wrapper in `main()`
+                if let Ok(client_addr) = CLIENT_ADDR.try_with(|addr| *addr) {
+                    if let Ok(destination) = stream.peer_addr() {
+                        let header = proxy_protocol::header(proxy_protocol.version, client_addr, destination);
+                        stream.write_all(&header).await?;
+                    }
+                }
+            }
+
+            Ok(UpstreamStream(stream, proxied))
+        })
+    }
+}